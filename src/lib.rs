@@ -58,18 +58,133 @@ use aws_sdk_sso::config::Region;
 use aws_sdk_sso::Client as SsoClient;
 use aws_sdk_ssooidc::operation::create_token::CreateTokenOutput;
 use aws_sdk_ssooidc::Client as SsoOidcClient;
+use base64::Engine;
+use chrono::{DateTime, Duration as ChronoDuration, SecondsFormat, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use skim::prelude::*;
 use std::error::Error;
 use std::fs;
-use std::io::{Cursor, Write};
+use std::io::{BufRead, BufReader, Cursor, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 use tokio::time::{sleep, Duration};
 
+/// Seconds of remaining lifetime below which a cached token is considered
+/// expired and the interactive flow is triggered again.
+const TOKEN_EXPIRY_SKEW_SECS: i64 = 60;
+
 #[derive(Default, Clone)]
 pub struct AwsSsoWorkflow {
     pub start_url: String,
     pub region: String,
+    /// When `true`, the OIDC access token is persisted to and reused from the
+    /// `~/.aws/sso/cache` directory, skipping the browser dance while the
+    /// cached token is still valid.
+    pub use_cache: bool,
+    /// When `true`, the first selected account/role is also written to the
+    /// `[default]` profile in addition to its named profile.
+    pub set_default: bool,
+    /// Which browser authentication flow to use. Defaults to the device-code
+    /// grant; [`AuthFlow::Pkce`] selects the authorization-code + PKCE flow,
+    /// which suits IdPs and headless setups that handle it better.
+    pub auth_flow: AuthFlow,
+}
+
+/// Selects the browser authentication flow used to obtain an OIDC token.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum AuthFlow {
+    /// OAuth2 device-authorization grant (the default).
+    #[default]
+    Device,
+    /// OAuth2 authorization-code grant with PKCE (`S256`).
+    Pkce,
+}
+
+/// On-disk representation of a cached SSO token.
+///
+/// The layout mirrors the JSON written by the official AWS CLI so the two
+/// tools can share the same `~/.aws/sso/cache/<sha1>.json` files. The cache key
+/// is the lowercase hex SHA-1 digest of the SSO start URL.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedToken {
+    start_url: String,
+    region: String,
+    access_token: String,
+    expires_at: String,
+    client_id: String,
+    client_secret: String,
+    registration_expires_at: String,
+    /// Long-lived refresh token, present only when the client registration
+    /// requested an offline scope and the IdP issued one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+}
+
+/// Declarative configuration for non-interactive, automation-friendly runs.
+///
+/// Loaded from a TOML or YAML file (selected by extension), it pins the
+/// `start_url`/`region` and lists the account/role `targets` to provision,
+/// each of which may be a concrete `account_id-role_name` key, a glob such as
+/// `*-ReadOnly`, or a logical alias defined in `mappings`.
+#[derive(Debug, Clone, Deserialize)]
+struct WorkflowConfig {
+    start_url: String,
+    region: String,
+    #[serde(default)]
+    set_default: bool,
+    #[serde(default)]
+    targets: Vec<String>,
+    #[serde(default)]
+    mappings: Vec<AliasMapping>,
+}
+
+/// Maps a stable logical alias (e.g. `admin`) to a concrete account/role so
+/// scripts can reference names that survive account renames.
+#[derive(Debug, Clone, Deserialize)]
+struct AliasMapping {
+    alias: String,
+    account_id: String,
+    role_name: String,
 }
 
+/// A request to the credential agent, one JSON object per connection.
+#[derive(Debug, Clone, Deserialize)]
+struct CredentialRequest {
+    account_id: String,
+    role_name: String,
+}
+
+/// Error payload returned to a credential-agent client, serialized with
+/// `serde_json` so special characters in the message stay valid JSON.
+#[derive(Debug, Clone, Serialize)]
+struct CredentialError {
+    error: String,
+}
+
+/// Role credentials serialized in the AWS `credential_process` format.
+///
+/// See the AWS CLI documentation for the external credential-process
+/// protocol: the `Version` must be `1` and `Expiration` is RFC3339.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CredentialProcessOutput {
+    version: u8,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiration: Option<String>,
+}
+
+/// OAuth2 scopes requested at client registration. `sso:account:access`
+/// grants access to the role-credential APIs, while the offline-access scope
+/// asks the IdP to issue a refresh token for silent renewal.
+const SSO_SCOPES: [&str; 2] = ["sso:account:access", "aws:offline-access"];
+
 pub struct Credential {
     pub account_id: String,
     pub role_name: String,
@@ -79,52 +194,166 @@ pub struct Credential {
 }
 
 impl AwsSsoWorkflow {
-    fn write_default_aws_credentials(
+    /// Creates a workflow that persists and reuses the OIDC access token via
+    /// the `~/.aws/sso/cache` directory. The first invocation performs the
+    /// usual device-authorization flow; subsequent invocations reuse the
+    /// cached token until it is within [`TOKEN_EXPIRY_SKEW_SECS`] of expiry.
+    pub fn with_cache(start_url: &str, region: &str) -> Self {
+        AwsSsoWorkflow {
+            start_url: start_url.to_string(),
+            region: region.to_string(),
+            use_cache: true,
+            ..Default::default()
+        }
+    }
+
+    /// Resolves the `~/.aws/sso/cache/<key>.json` path for a start URL, where
+    /// `<key>` is the lowercase hex SHA-1 digest of the URL.
+    fn cache_path(start_url: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let digest = Sha1::digest(start_url.as_bytes());
+        let key: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        dirs_next::home_dir()
+            .map(|home| home.join(".aws/sso/cache").join(format!("{}.json", key)))
+            .ok_or_else(|| "Could not locate home directory".into())
+    }
+
+    /// Reads a cached token for `start_url`, returning `None` when the file is
+    /// absent or cannot be parsed.
+    fn load_cached_token(start_url: &str) -> Option<CachedToken> {
+        let path = Self::cache_path(start_url).ok()?;
+        let contents = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// Returns `true` when the cached token still has more than
+    /// [`TOKEN_EXPIRY_SKEW_SECS`] of lifetime remaining.
+    fn cached_token_is_usable(token: &CachedToken) -> bool {
+        DateTime::parse_from_rfc3339(&token.expires_at)
+            .map(|expiry| {
+                expiry.with_timezone(&Utc)
+                    > Utc::now() + ChronoDuration::seconds(TOKEN_EXPIRY_SKEW_SECS)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Writes a token to the cache, creating the cache directory if needed.
+    fn save_cached_token(token: &CachedToken) -> Result<(), Box<dyn Error>> {
+        let path = Self::cache_path(&token.start_url)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, serde_json::to_string_pretty(token)?)?;
+        println!("Cached SSO token written to: {:?}", path);
+        Ok(())
+    }
+
+    /// Splits the contents of an `~/.aws/credentials` file into a preamble and
+    /// its `[section]` blocks, preserving order and the exact source lines.
+    ///
+    /// The preamble holds any comment/blank lines that precede the first
+    /// header; each section keeps its body lines verbatim (comments, blanks and
+    /// original spacing included) so that untouched profiles round-trip
+    /// unchanged.
+    fn parse_credentials_sections(contents: &str) -> (Vec<String>, Vec<(String, Vec<String>)>) {
+        let mut preamble: Vec<String> = Vec::new();
+        let mut sections: Vec<(String, Vec<String>)> = Vec::new();
+        for line in contents.lines() {
+            let trimmed = line.trim();
+            if let Some(name) = trimmed.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+                sections.push((name.to_string(), Vec::new()));
+            } else if let Some((_, body)) = sections.last_mut() {
+                body.push(line.to_string());
+            } else {
+                preamble.push(line.to_string());
+            }
+        }
+        (preamble, sections)
+    }
+
+    /// Inserts or updates a single named profile in `~/.aws/credentials`,
+    /// leaving every other profile — along with the file's preamble, comments
+    /// and spacing — untouched.
+    fn upsert_aws_profile(
+        profile_name: &str,
         access_key_id: &str,
         secret_access_key: &str,
         session_token: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        dirs_next::home_dir()
-                .map(|home| home.join(".aws/credentials"))
-                .ok_or_else(|| "Could not locate home directory".into())
-                .and_then(|credentials_path| {
-                    credentials_path
-                        .parent()
-                        .map(fs::create_dir_all)
-                        .transpose()
-                        .map_err(|e| e.into())
-                        .and_then(|_| {
-                            std::fs::write(
-                                &credentials_path,
-                                format!(
-                                    "[default]\naws_access_key_id = {}\naws_secret_access_key = {}\naws_session_token = {}\n",
-                                    access_key_id, secret_access_key, session_token
-                                ),
-                            )
-                            .map(|_| {
-                                println!("Default credentials written to: {:?}", credentials_path);
-                            })
-                            .map_err(|e| e.into())
-                        })
-                })
+        let credentials_path = dirs_next::home_dir()
+            .map(|home| home.join(".aws/credentials"))
+            .ok_or("Could not locate home directory")?;
+        if let Some(parent) = credentials_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let existing = fs::read_to_string(&credentials_path).unwrap_or_default();
+        let (preamble, mut sections) = Self::parse_credentials_sections(&existing);
+
+        let body = vec![
+            format!("aws_access_key_id = {}", access_key_id),
+            format!("aws_secret_access_key = {}", secret_access_key),
+            format!("aws_session_token = {}", session_token),
+        ];
+        match sections.iter_mut().find(|(name, _)| name == profile_name) {
+            Some((_, existing_body)) => *existing_body = body,
+            None => sections.push((profile_name.to_string(), body)),
+        }
+
+        let mut rendered = String::new();
+        for line in &preamble {
+            rendered.push_str(line);
+            rendered.push('\n');
+        }
+        for (name, body) in &sections {
+            rendered.push_str(&format!("[{}]\n", name));
+            for line in body {
+                rendered.push_str(line);
+                rendered.push('\n');
+            }
+        }
+        fs::write(&credentials_path, rendered)?;
+        println!(
+            "Profile [{}] written to: {:?}",
+            profile_name, credentials_path
+        );
+        Ok(())
     }
 
+    /// Registers an OIDC client.
+    ///
+    /// AWS SSO OIDC takes the requested scopes and grant types at client
+    /// registration — `StartDeviceAuthorization` has no scope parameter — so
+    /// the offline/`sso:account:access` scopes and the `refresh_token` grant
+    /// are requested here. A client registered this way is what lets the
+    /// subsequent device-code `CreateToken` return a `refresh_token` that
+    /// [`refresh_credentials`](Self::refresh_credentials) can later redeem.
     async fn register_client(
         sso_oidc_client: &SsoOidcClient,
         client_name: &str,
         client_type: &str,
-    ) -> Result<(String, String), Box<dyn Error>> {
-        sso_oidc_client
-            .register_client()
-            .client_name(client_name)
-            .client_type(client_type)
+    ) -> Result<(String, String, i64), Box<dyn Error>> {
+        SSO_SCOPES
+            .iter()
+            .fold(
+                sso_oidc_client
+                    .register_client()
+                    .client_name(client_name)
+                    .client_type(client_type)
+                    .grant_types("urn:ietf:params:oauth:grant-type:device_code")
+                    .grant_types("refresh_token"),
+                |builder, scope| builder.scopes(*scope),
+            )
             .send()
             .await
             .map_err(|e| Box::new(e) as Box<dyn Error>)
             .and_then(|response| {
                 let client_id = response.client_id().ok_or("Missing client_id")?;
                 let client_secret = response.client_secret().ok_or("Missing client_secret")?;
-                Ok((client_id.to_string(), client_secret.to_string()))
+                Ok((
+                    client_id.to_string(),
+                    client_secret.to_string(),
+                    response.client_secret_expires_at(),
+                ))
             })
     }
 
@@ -202,7 +431,10 @@ impl AwsSsoWorkflow {
         sso_client: &SsoClient,
         access_token: &str,
         selected_items: Vec<String>,
+        set_default: bool,
     ) -> Result<Credential, Box<dyn std::error::Error>> {
+        let mut first_credential: Option<Credential> = None;
+
         for selected_output in selected_items {
             let parts: Vec<&str> = selected_output.split(" - ").collect();
             if parts.len() != 3 {
@@ -211,6 +443,7 @@ impl AwsSsoWorkflow {
             }
 
             let account_id = parts[0];
+            let account_name = parts[1];
             let role_name = parts[2];
 
             println!(
@@ -218,40 +451,67 @@ impl AwsSsoWorkflow {
                 account_id, role_name
             );
 
-            let credentials_resp = sso_client
+            let credentials_resp = match sso_client
                 .get_role_credentials()
                 .account_id(account_id)
                 .role_name(role_name)
                 .access_token(access_token)
                 .send()
-                .await?;
+                .await
+            {
+                Ok(resp) => resp,
+                Err(e) => {
+                    // Don't discard the rest of a multi-selection batch because
+                    // one role is inaccessible (AccessDenied, throttling, …);
+                    // log and move on to the next selection.
+                    eprintln!(
+                        "Failed to fetch credentials for Account ID: {}, Role: {}: {}",
+                        account_id, role_name, e
+                    );
+                    continue;
+                }
+            };
 
             if let Some(credentials) = credentials_resp.role_credentials() {
                 let access_key_id = credentials.access_key_id().unwrap_or("").to_string();
                 let secret_access_key = credentials.secret_access_key().unwrap_or("").to_string();
                 let session_token = credentials.session_token().unwrap_or("").to_string();
 
-                let creds = Credential {
-                    account_id: account_id.to_string(),
-                    role_name: role_name.to_string(),
-                    access_key_id: access_key_id.clone(),
-                    secret_access_key: secret_access_key.clone(),
-                    session_token: session_token.clone(),
-                };
-
                 println!(
                     "Credentials fetched for Account ID: {}, Role: {}",
                     account_id, role_name
                 );
 
-                // Optional: Write credentials to AWS config
-                AwsSsoWorkflow::write_default_aws_credentials(
+                // Write each selection to its own `[account_name-role_name]`
+                // profile, preserving any unrelated profiles already present.
+                let profile_name = format!("{}-{}", account_name, role_name);
+                AwsSsoWorkflow::upsert_aws_profile(
+                    &profile_name,
                     &access_key_id,
                     &secret_access_key,
                     &session_token,
                 )?;
 
-                return Ok(creds); // Return the first successfully fetched credentials
+                // Optionally mirror the first *successfully written* selection
+                // into `[default]` (entries skipped as malformed don't count).
+                if set_default && first_credential.is_none() {
+                    AwsSsoWorkflow::upsert_aws_profile(
+                        "default",
+                        &access_key_id,
+                        &secret_access_key,
+                        &session_token,
+                    )?;
+                }
+
+                if first_credential.is_none() {
+                    first_credential = Some(Credential {
+                        account_id: account_id.to_string(),
+                        role_name: role_name.to_string(),
+                        access_key_id,
+                        secret_access_key,
+                        session_token,
+                    });
+                }
             } else {
                 eprintln!(
                     "Failed to fetch credentials for Account ID: {}, Role: {}",
@@ -260,7 +520,74 @@ impl AwsSsoWorkflow {
             }
         }
 
-        Err("No valid credentials found".into()) // Return an error if no credentials were fetched
+        first_credential.ok_or_else(|| "No valid credentials found".into())
+    }
+
+    /// Matches a string against a shell-style glob supporting `*` (any run of
+    /// characters) and `?` (a single character). Used to resolve config
+    /// targets such as `*-ReadOnly` against account/role keys.
+    fn glob_match(pattern: &str, value: &str) -> bool {
+        let pat: Vec<char> = pattern.chars().collect();
+        let val: Vec<char> = value.chars().collect();
+
+        fn matches(pat: &[char], val: &[char]) -> bool {
+            match pat.first() {
+                None => val.is_empty(),
+                Some('*') => {
+                    matches(&pat[1..], val)
+                        || (!val.is_empty() && matches(pat, &val[1..]))
+                }
+                Some('?') => !val.is_empty() && matches(&pat[1..], &val[1..]),
+                Some(&c) => {
+                    !val.is_empty() && val[0] == c && matches(&pat[1..], &val[1..])
+                }
+            }
+        }
+
+        matches(&pat, &val)
+    }
+
+    /// Resolves the configured `targets` against the discovered account/role
+    /// list, returning selections in the `account_id - account_name -
+    /// role_name` form the rest of the workflow expects.
+    ///
+    /// A target is first looked up as a `mappings` alias; otherwise it is
+    /// treated as a glob matched against both the `account_id-role_name` and
+    /// `account_name-role_name` keys of each candidate.
+    fn resolve_config_targets(config: &WorkflowConfig, account_role_strings: &[String]) -> Vec<String> {
+        let mut selected = Vec::new();
+
+        for target in &config.targets {
+            let mapped = config
+                .mappings
+                .iter()
+                .find(|mapping| mapping.alias == *target)
+                .map(|mapping| (mapping.account_id.clone(), mapping.role_name.clone()));
+
+            for candidate in account_role_strings {
+                let parts: Vec<&str> = candidate.split(" - ").collect();
+                if parts.len() != 3 {
+                    continue;
+                }
+                let (account_id, account_name, role_name) = (parts[0], parts[1], parts[2]);
+
+                let is_match = match &mapped {
+                    Some((mapped_id, mapped_role)) => {
+                        account_id == mapped_id && role_name == mapped_role
+                    }
+                    None => {
+                        Self::glob_match(target, &format!("{}-{}", account_id, role_name))
+                            || Self::glob_match(target, &format!("{}-{}", account_name, role_name))
+                    }
+                };
+
+                if is_match && !selected.contains(candidate) {
+                    selected.push(candidate.clone());
+                }
+            }
+        }
+
+        selected
     }
 
     fn perform_fuzzy_search(
@@ -344,29 +671,18 @@ impl AwsSsoWorkflow {
         Ok(input.trim().to_string())
     }
 
-    pub async fn run_workflow(&mut self) -> Result<Credential, Box<dyn Error>> {
-        self.start_url = Self::prompt_input("Enter the AWS start URL")?;
-
-        self.region = Self::prompt_input("Enter the AWS region")?;
-
-        println!(
-            "Running AWS workflow with URL: {} and region: {}",
-            self.start_url, self.region
-        );
-
-        let config: aws_config::SdkConfig = aws_config::defaults(BehaviorVersion::v2024_03_28())
-            .region(Region::new(self.region.clone()))
-            .load()
-            .await;
-
-        let sso_oidc_client = SsoOidcClient::new(&config);
-
-        let (client_id, client_secret) =
-            Self::register_client(&sso_oidc_client, "my-rust-sso-client", "public").await?;
+    /// Performs the interactive device-authorization flow and, when caching is
+    /// enabled, persists the resulting token to `~/.aws/sso/cache`.
+    async fn authenticate_interactively(
+        &self,
+        sso_oidc_client: &SsoOidcClient,
+    ) -> Result<String, Box<dyn Error>> {
+        let (client_id, client_secret, registration_expires_at) =
+            Self::register_client(sso_oidc_client, "my-rust-sso-client", "public").await?;
 
         let (device_code, user_code, verification_uri, verification_uri_complete, interval) =
             Self::start_device_authorization(
-                &sso_oidc_client,
+                sso_oidc_client,
                 &client_id,
                 &client_secret,
                 &self.start_url,
@@ -390,7 +706,7 @@ impl AwsSsoWorkflow {
         std::io::stdin().read_line(&mut input)?;
 
         let token_response = Self::poll_for_token(
-            &sso_oidc_client,
+            sso_oidc_client,
             &client_id,
             &client_secret,
             &device_code,
@@ -398,13 +714,539 @@ impl AwsSsoWorkflow {
         )
         .await?;
 
-        let sso_client = SsoClient::new(&config);
+        println!("Access token retrieved successfully.");
+        self.persist_token(client_id, client_secret, registration_expires_at, &token_response)
+    }
+
+    /// Performs the OAuth2 authorization-code + PKCE flow.
+    ///
+    /// Generates a random `code_verifier`, derives the `S256`
+    /// `code_challenge`, binds a localhost listener to an ephemeral port for
+    /// the redirect callback, opens the browser to the authorize endpoint, and
+    /// exchanges the captured `code` via `CreateToken` with
+    /// `grant_type=authorization_code`. The resulting token is cached like the
+    /// device flow's.
+    async fn authenticate_pkce(
+        &self,
+        sso_oidc_client: &SsoOidcClient,
+    ) -> Result<String, Box<dyn Error>> {
+        // Bind the redirect listener first so its port is known before the
+        // client is registered with the matching redirect URI.
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let redirect_uri = format!("http://{}/callback", listener.local_addr()?);
+
+        let (client_id, client_secret, registration_expires_at) = SSO_SCOPES
+            .iter()
+            .fold(
+                sso_oidc_client
+                    .register_client()
+                    .client_name("my-rust-sso-client")
+                    .client_type("public")
+                    .grant_types("authorization_code")
+                    .grant_types("refresh_token")
+                    .redirect_uris(&redirect_uri),
+                |builder, scope| builder.scopes(*scope),
+            )
+            .send()
+            .await
+            .map_err(|e| Box::new(e) as Box<dyn Error>)
+            .and_then(|response| {
+                let client_id = response.client_id().ok_or("Missing client_id")?;
+                let client_secret = response.client_secret().ok_or("Missing client_secret")?;
+                Ok((
+                    client_id.to_string(),
+                    client_secret.to_string(),
+                    response.client_secret_expires_at(),
+                ))
+            })?;
+
+        let code_verifier = Self::generate_code_verifier();
+        let code_challenge = Self::code_challenge(&code_verifier);
+
+        let authorize_url = format!(
+            "https://oidc.{}.amazonaws.com/authorize?response_type=code&client_id={}&redirect_uri={}&code_challenge={}&code_challenge_method=S256&scope={}",
+            self.region,
+            Self::percent_encode(&client_id),
+            Self::percent_encode(&redirect_uri),
+            code_challenge,
+            Self::percent_encode(&SSO_SCOPES.join(" ")),
+        );
+
+        println!("Opening the authorization page in your browser...");
+        if webbrowser::open(&authorize_url).is_err() {
+            println!("Could not open the browser. Please go to: {}", authorize_url);
+        }
+
+        let code = Self::await_authorization_code(&listener)?;
+
+        let token_response = sso_oidc_client
+            .create_token()
+            .client_id(&client_id)
+            .client_secret(&client_secret)
+            .grant_type("authorization_code")
+            .code(&code)
+            .redirect_uri(&redirect_uri)
+            .code_verifier(&code_verifier)
+            .send()
+            .await
+            .map_err(|e| Box::new(e) as Box<dyn Error>)?;
 
-        let access_token = Self::extract_access_token(&token_response)?;
         println!("Access token retrieved successfully.");
+        self.persist_token(client_id, client_secret, registration_expires_at, &token_response)
+    }
+
+    /// Percent-encodes a query-string component, leaving only the RFC 3986
+    /// unreserved characters untouched (so spaces become `%20`, `:`/`/` in the
+    /// redirect URI are escaped, etc.).
+    fn percent_encode(value: &str) -> String {
+        value
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                    (byte as char).to_string()
+                }
+                _ => format!("%{:02X}", byte),
+            })
+            .collect()
+    }
+
+    /// Generates a high-entropy PKCE `code_verifier` as base64url-encoded
+    /// random bytes (RFC 7636).
+    fn generate_code_verifier() -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    /// Derives the `S256` `code_challenge` as the base64url-encoded SHA-256 of
+    /// the verifier.
+    fn code_challenge(verifier: &str) -> String {
+        let digest = Sha256::digest(verifier.as_bytes());
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(digest)
+    }
+
+    /// Percent-decodes a query-string value, turning `%XX` escapes back into
+    /// their bytes (the inverse of [`percent_encode`](Self::percent_encode)).
+    fn percent_decode(value: &str) -> String {
+        let bytes = value.as_bytes();
+        let mut out = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'%' && i + 2 < bytes.len() {
+                if let Ok(byte) = u8::from_str_radix(&value[i + 1..i + 3], 16) {
+                    out.push(byte);
+                    i += 3;
+                    continue;
+                }
+            }
+            out.push(bytes[i]);
+            i += 1;
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    }
+
+    /// Waits for the authorization redirect on the callback listener.
+    ///
+    /// Accepts connections until one carries a `code` query parameter —
+    /// ignoring favicon/probe requests and error redirects — bounded by a
+    /// timeout, and returns the percent-decoded authorization code.
+    fn await_authorization_code(listener: &TcpListener) -> Result<String, Box<dyn Error>> {
+        use std::time::{Duration as StdDuration, Instant};
+
+        println!("Waiting for the authorization redirect...");
+        listener.set_nonblocking(true)?;
+        let deadline = Instant::now() + StdDuration::from_secs(120);
+
+        loop {
+            if Instant::now() >= deadline {
+                return Err("Timed out waiting for the authorization redirect".into());
+            }
+
+            let mut stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(StdDuration::from_millis(100));
+                    continue;
+                }
+                Err(e) => return Err(Box::new(e)),
+            };
+
+            let mut request_line = String::new();
+            BufReader::new(&stream).read_line(&mut request_line)?;
+
+            // Request line looks like: `GET /callback?code=...&state=... HTTP/1.1`.
+            let code = request_line
+                .split_whitespace()
+                .nth(1)
+                .and_then(|target| target.split_once('?').map(|(_, query)| query))
+                .and_then(|query| {
+                    query
+                        .split('&')
+                        .filter_map(|pair| pair.split_once('='))
+                        .find(|(key, _)| *key == "code")
+                        .map(|(_, value)| Self::percent_decode(value))
+                });
+
+            // Acknowledge every request; only one carrying `code` ends the loop.
+            let body = if code.is_some() {
+                "<html><body>Authentication complete. You may close this window.</body></html>"
+            } else {
+                "<html><body>Waiting for authorization...</body></html>"
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes())?;
+
+            if let Some(code) = code {
+                return Ok(code);
+            }
+        }
+    }
+
+    /// Extracts the access token from a `CreateToken` response and, when
+    /// caching is enabled, writes it (with the client registration and any
+    /// refresh token) to `~/.aws/sso/cache`.
+    fn persist_token(
+        &self,
+        client_id: String,
+        client_secret: String,
+        registration_expires_at: i64,
+        token_response: &CreateTokenOutput,
+    ) -> Result<String, Box<dyn Error>> {
+        let access_token = Self::extract_access_token(token_response)?.to_string();
+
+        if self.use_cache {
+            let expires_at = (Utc::now()
+                + ChronoDuration::seconds(token_response.expires_in() as i64))
+            .to_rfc3339_opts(SecondsFormat::Secs, true);
+            let registration_expires_at = DateTime::from_timestamp(registration_expires_at, 0)
+                .unwrap_or_else(Utc::now)
+                .to_rfc3339_opts(SecondsFormat::Secs, true);
+            Self::save_cached_token(&CachedToken {
+                start_url: self.start_url.clone(),
+                region: self.region.clone(),
+                access_token: access_token.clone(),
+                expires_at,
+                client_id,
+                client_secret,
+                registration_expires_at,
+                refresh_token: token_response.refresh_token().map(str::to_string),
+            })?;
+        }
+
+        Ok(access_token)
+    }
+
+    /// Mints a fresh access token from the cached refresh token without
+    /// reopening the browser.
+    ///
+    /// Loads the cached token for `self.start_url`, exchanges its
+    /// `refresh_token` via `CreateToken` with `grant_type=refresh_token`, and
+    /// rewrites the cache with the renewed access token (and any rotated
+    /// refresh token). Returns an error when no cached refresh token exists.
+    pub async fn refresh_credentials(&self) -> Result<String, Box<dyn Error>> {
+        let cached = Self::load_cached_token(&self.start_url)
+            .ok_or("No cached SSO token to refresh")?;
+        let refresh_token = cached
+            .refresh_token
+            .clone()
+            .ok_or("Cached SSO token has no refresh token")?;
+
+        let config: aws_config::SdkConfig = aws_config::defaults(BehaviorVersion::v2024_03_28())
+            .region(Region::new(self.region.clone()))
+            .load()
+            .await;
+        let sso_oidc_client = SsoOidcClient::new(&config);
+
+        let token_response = SSO_SCOPES
+            .iter()
+            .fold(
+                sso_oidc_client
+                    .create_token()
+                    .client_id(&cached.client_id)
+                    .client_secret(&cached.client_secret)
+                    .grant_type("refresh_token")
+                    .refresh_token(&refresh_token),
+                |builder, scope| builder.scope(*scope),
+            )
+            .send()
+            .await
+            .map_err(|e| Box::new(e) as Box<dyn Error>)?;
+
+        let access_token = Self::extract_access_token(&token_response)?.to_string();
+        let expires_at = (Utc::now()
+            + ChronoDuration::seconds(token_response.expires_in() as i64))
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+
+        Self::save_cached_token(&CachedToken {
+            access_token: access_token.clone(),
+            expires_at,
+            refresh_token: token_response
+                .refresh_token()
+                .map(str::to_string)
+                .or(Some(refresh_token)),
+            ..cached
+        })?;
+        println!("Refreshed SSO access token from cached refresh token.");
+
+        Ok(access_token)
+    }
+
+    /// Returns a usable SSO access token, refreshing it from the cached
+    /// refresh token when the cached access token is expired.
+    async fn valid_access_token(&self) -> Result<String, Box<dyn Error>> {
+        match Self::load_cached_token(&self.start_url).filter(Self::cached_token_is_usable) {
+            Some(cached) => Ok(cached.access_token),
+            None => self.refresh_credentials().await,
+        }
+    }
+
+    /// Fetches role credentials for an account/role and serializes them in the
+    /// AWS `credential_process` format, transparently refreshing the SSO token
+    /// when it has expired.
+    async fn role_credentials_json(
+        &self,
+        account_id: &str,
+        role_name: &str,
+    ) -> Result<String, Box<dyn Error>> {
+        let config: aws_config::SdkConfig = aws_config::defaults(BehaviorVersion::v2024_03_28())
+            .region(Region::new(self.region.clone()))
+            .load()
+            .await;
+
+        let access_token = self.valid_access_token().await?;
+        let sso_client = SsoClient::new(&config);
+
+        let response = sso_client
+            .get_role_credentials()
+            .account_id(account_id)
+            .role_name(role_name)
+            .access_token(&access_token)
+            .send()
+            .await?;
+
+        let credentials = response
+            .role_credentials()
+            .ok_or("No role credentials returned")?;
+
+        let expiration = DateTime::from_timestamp_millis(credentials.expiration())
+            .map(|expiry| expiry.to_rfc3339_opts(SecondsFormat::Secs, true));
+
+        let output = CredentialProcessOutput {
+            version: 1,
+            access_key_id: credentials.access_key_id().unwrap_or("").to_string(),
+            secret_access_key: credentials.secret_access_key().unwrap_or("").to_string(),
+            session_token: credentials.session_token().unwrap_or("").to_string(),
+            expiration,
+        };
+
+        Ok(serde_json::to_string(&output)?)
+    }
+
+    /// Client entrypoint for use as an external `credential_process`.
+    ///
+    /// A thin binary can wire this up so that `credential_process = aws_sso
+    /// fetch --account X --role Y` in an AWS profile prints the JSON this
+    /// returns on stdout.
+    pub async fn fetch_credential_process(
+        &self,
+        account_id: &str,
+        role_name: &str,
+    ) -> Result<String, Box<dyn Error>> {
+        self.role_credentials_json(account_id, role_name).await
+    }
+
+    /// Serializes an error message as a JSON `{ "error": ... }` object, safely
+    /// escaping any quotes, backslashes or newlines it contains.
+    fn error_json(message: String) -> String {
+        serde_json::to_string(&CredentialError { error: message })
+            .unwrap_or_else(|_| String::from("{\"error\":\"internal error\"}"))
+    }
+
+    /// Runs a long-lived agent that vends credentials over a Unix domain
+    /// socket.
+    ///
+    /// Each connection carries a single JSON `{ "account_id", "role_name" }`
+    /// request and receives a `credential_process`-format JSON response,
+    /// refreshing the cached SSO token on demand so multiple processes can
+    /// share one authenticated session.
+    #[cfg(unix)]
+    pub async fn serve(&self, socket_path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use tokio::net::UnixListener;
+
+        let socket_path = socket_path.as_ref();
+        if socket_path.exists() {
+            fs::remove_file(socket_path)?;
+        }
+        let listener = UnixListener::bind(socket_path)?;
+        println!("Credential agent listening on {:?}", socket_path);
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let (read_half, mut write_half) = stream.into_split();
+
+            let mut request_line = String::new();
+            BufReader::new(read_half).read_line(&mut request_line).await?;
+
+            let response = match serde_json::from_str::<CredentialRequest>(request_line.trim()) {
+                Ok(request) => self
+                    .role_credentials_json(&request.account_id, &request.role_name)
+                    .await
+                    .unwrap_or_else(|e| Self::error_json(e.to_string())),
+                Err(e) => Self::error_json(e.to_string()),
+            };
+
+            write_half.write_all(response.as_bytes()).await?;
+            write_half.write_all(b"\n").await?;
+        }
+    }
+
+    /// Windows named-pipe variant of [`serve`](Self::serve).
+    #[cfg(windows)]
+    pub async fn serve(&self, pipe_name: impl AsRef<str>) -> Result<(), Box<dyn Error>> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let pipe_name = pipe_name.as_ref();
+        println!("Credential agent listening on {}", pipe_name);
+
+        loop {
+            let server = ServerOptions::new().create(pipe_name)?;
+            server.connect().await?;
+
+            let (read_half, mut write_half) = tokio::io::split(server);
+
+            let mut request_line = String::new();
+            BufReader::new(read_half).read_line(&mut request_line).await?;
+
+            let response = match serde_json::from_str::<CredentialRequest>(request_line.trim()) {
+                Ok(request) => self
+                    .role_credentials_json(&request.account_id, &request.role_name)
+                    .await
+                    .unwrap_or_else(|e| Self::error_json(e.to_string())),
+                Err(e) => Self::error_json(e.to_string()),
+            };
+
+            write_half.write_all(response.as_bytes()).await?;
+            write_half.write_all(b"\n").await?;
+        }
+    }
+
+    /// Runs the workflow non-interactively from a TOML or YAML config file.
+    ///
+    /// The config pins `start_url`/`region` and lists the account/role
+    /// `targets` (and optional `mappings`) to provision; the skim fuzzy picker
+    /// and `prompt_input` calls are skipped entirely. Returns the first
+    /// credential that was fetched, having written a named profile for each
+    /// resolved target.
+    pub async fn run_with_config(path: impl AsRef<Path>) -> Result<Credential, Box<dyn Error>> {
+        let contents = fs::read_to_string(path.as_ref())?;
+        let config: WorkflowConfig = match path
+            .as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+        {
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&contents)?,
+            _ => toml::from_str(&contents)?,
+        };
+
+        let workflow = AwsSsoWorkflow {
+            start_url: config.start_url.clone(),
+            region: config.region.clone(),
+            use_cache: true,
+            set_default: config.set_default,
+            ..Default::default()
+        };
+
+        println!(
+            "Running AWS workflow from config with URL: {} and region: {}",
+            workflow.start_url, workflow.region
+        );
+
+        let sdk_config: aws_config::SdkConfig =
+            aws_config::defaults(BehaviorVersion::v2024_03_28())
+                .region(Region::new(workflow.region.clone()))
+                .load()
+                .await;
+
+        let access_token = workflow.acquire_access_token(&sdk_config).await?;
+        let sso_client = SsoClient::new(&sdk_config);
 
         let account_role_strings =
-            Self::fetch_accounts_and_roles(&sso_client, access_token).await?;
+            Self::fetch_accounts_and_roles(&sso_client, &access_token).await?;
+        if account_role_strings.is_empty() {
+            return Err("No accounts or roles found".into());
+        }
+
+        let selected_items = Self::resolve_config_targets(&config, &account_role_strings);
+        if selected_items.is_empty() {
+            return Err("No configured targets matched the available accounts and roles".into());
+        }
+
+        Self::process_selected_accounts_and_roles(
+            &sso_client,
+            &access_token,
+            selected_items,
+            workflow.set_default,
+        )
+        .await
+    }
+
+    /// Returns a usable access token, reusing the cache when enabled and
+    /// otherwise driving the interactive flow selected by `auth_flow`
+    /// (device-code or PKCE).
+    async fn acquire_access_token(
+        &self,
+        config: &aws_config::SdkConfig,
+    ) -> Result<String, Box<dyn Error>> {
+        match self
+            .use_cache
+            .then(|| Self::load_cached_token(&self.start_url))
+            .flatten()
+            .filter(Self::cached_token_is_usable)
+        {
+            Some(cached) => {
+                println!("Reusing cached SSO token.");
+                Ok(cached.access_token)
+            }
+            None => {
+                let sso_oidc_client = SsoOidcClient::new(config);
+                match self.auth_flow {
+                    AuthFlow::Device => self.authenticate_interactively(&sso_oidc_client).await,
+                    AuthFlow::Pkce => self.authenticate_pkce(&sso_oidc_client).await,
+                }
+            }
+        }
+    }
+
+    pub async fn run_workflow(&mut self) -> Result<Credential, Box<dyn Error>> {
+        if self.start_url.is_empty() {
+            self.start_url = Self::prompt_input("Enter the AWS start URL")?;
+        }
+        if self.region.is_empty() {
+            self.region = Self::prompt_input("Enter the AWS region")?;
+        }
+
+        println!(
+            "Running AWS workflow with URL: {} and region: {}",
+            self.start_url, self.region
+        );
+
+        let config: aws_config::SdkConfig = aws_config::defaults(BehaviorVersion::v2024_03_28())
+            .region(Region::new(self.region.clone()))
+            .load()
+            .await;
+
+        let access_token = self.acquire_access_token(&config).await?;
+
+        let sso_client = SsoClient::new(&config);
+
+        let account_role_strings =
+            Self::fetch_accounts_and_roles(&sso_client, &access_token).await?;
         if account_role_strings.is_empty() {
             println!("No accounts or roles found.");
             return Err("No accounts or roles found".into());
@@ -417,9 +1259,97 @@ impl AwsSsoWorkflow {
         }
 
         let credentials =
-            Self::process_selected_accounts_and_roles(&sso_client, access_token, selected_items)
+            Self::process_selected_accounts_and_roles(
+                &sso_client,
+                &access_token,
+                selected_items,
+                self.set_default,
+            )
                 .await?;
 
         Ok(credentials)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_handles_wildcards() {
+        assert!(AwsSsoWorkflow::glob_match("*-ReadOnly", "111111111111-ReadOnly"));
+        assert!(AwsSsoWorkflow::glob_match("*", "anything"));
+        assert!(AwsSsoWorkflow::glob_match("Prod-?????", "Prod-Admin"));
+        assert!(!AwsSsoWorkflow::glob_match("*-ReadOnly", "111111111111-Admin"));
+        assert!(!AwsSsoWorkflow::glob_match("Prod-?", "Prod-Admin"));
+    }
+
+    #[test]
+    fn parse_credentials_sections_preserves_preamble_and_bodies() {
+        let input = "# managed by hand\n\n[keep]\naws_access_key_id = AKIA\n# note\n";
+        let (preamble, sections) = AwsSsoWorkflow::parse_credentials_sections(input);
+
+        assert_eq!(preamble, vec!["# managed by hand".to_string(), String::new()]);
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].0, "keep");
+        assert_eq!(
+            sections[0].1,
+            vec!["aws_access_key_id = AKIA".to_string(), "# note".to_string()]
+        );
+    }
+
+    #[test]
+    fn cached_token_is_usable_respects_expiry() {
+        let make = |expires_at: String| CachedToken {
+            start_url: "https://example.awsapps.com/start".to_string(),
+            region: "eu-west-1".to_string(),
+            access_token: "token".to_string(),
+            expires_at,
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            registration_expires_at: "2099-01-01T00:00:00Z".to_string(),
+            refresh_token: None,
+        };
+
+        let fresh = (Utc::now() + ChronoDuration::hours(1))
+            .to_rfc3339_opts(SecondsFormat::Secs, true);
+        let expired = (Utc::now() - ChronoDuration::hours(1))
+            .to_rfc3339_opts(SecondsFormat::Secs, true);
+
+        assert!(AwsSsoWorkflow::cached_token_is_usable(&make(fresh)));
+        assert!(!AwsSsoWorkflow::cached_token_is_usable(&make(expired)));
+        assert!(!AwsSsoWorkflow::cached_token_is_usable(&make(
+            "not-a-date".to_string()
+        )));
+    }
+
+    #[test]
+    fn resolve_config_targets_matches_globs_and_aliases() {
+        let config = WorkflowConfig {
+            start_url: "https://example.awsapps.com/start".to_string(),
+            region: "eu-west-1".to_string(),
+            set_default: false,
+            targets: vec!["*-ReadOnly".to_string(), "admin".to_string()],
+            mappings: vec![AliasMapping {
+                alias: "admin".to_string(),
+                account_id: "222222222222".to_string(),
+                role_name: "Admin".to_string(),
+            }],
+        };
+        let available = vec![
+            "111111111111 - Prod - ReadOnly".to_string(),
+            "222222222222 - Staging - Admin".to_string(),
+            "333333333333 - Dev - PowerUser".to_string(),
+        ];
+
+        let selected = AwsSsoWorkflow::resolve_config_targets(&config, &available);
+
+        assert_eq!(
+            selected,
+            vec![
+                "111111111111 - Prod - ReadOnly".to_string(),
+                "222222222222 - Staging - Admin".to_string(),
+            ]
+        );
+    }
+}